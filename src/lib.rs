@@ -4,32 +4,58 @@ use sha2::Sha256;
 type HmacSha256 = Hmac<Sha256>;
 use constant_time_eq::constant_time_eq;
 use std::collections::HashMap;
-use std::error::Error;
-use std::fmt::{Debug, Display};
+use std::io;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use thiserror::Error as ThisError;
 
-#[derive(Debug)]
-struct InvalidKeyValuePair;
+/// Default replay-protection window used by [`verify`], matching Stripe's
+/// reference implementations (e.g. stripe-go, stripe-python).
+pub const DEFAULT_TOLERANCE: Duration = Duration::from_secs(300);
 
-impl Display for InvalidKeyValuePair {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "invalid key value pair")
-    }
+/// Errors returned while parsing or verifying a `Stripe-Signature` header.
+#[derive(Debug, ThisError)]
+pub enum Error {
+    #[error("malformed Stripe-Signature header")]
+    MalformedHeader,
+    #[error("missing t (timestamp) entry in Stripe-Signature header")]
+    MissingTimestamp,
+    #[error("missing v1 (signature) entry in Stripe-Signature header")]
+    MissingSignature,
+    #[error("timestamp is {age:?} away from the current time, outside of the allowed tolerance")]
+    TimestampOutOfTolerance { age: Duration },
+    #[error("v1 (signature) entry is not valid hex")]
+    InvalidSignatureEncoding,
+    #[error("signature does not match expected value")]
+    SignatureMismatch,
 }
-impl Error for InvalidKeyValuePair {}
 
-pub(crate) fn compute_signature(payload: &str, secret: &str) -> String {
-    let mut mac = HmacSha256::new_varkey(secret.as_bytes()).expect("HMAC can take key of any size");
-    mac.update(payload.as_bytes());
+/// Computes the hex-encoded `v1` HMAC-SHA256 signature for a `{timestamp}.{payload}`
+/// signed payload, mirroring stripe-go's `ComputeSignature(t, payload, secret)`.
+///
+/// This is the same signature scheme `verify` checks against, exposed so
+/// webhook senders and integration tests can produce valid `Stripe-Signature`
+/// headers without depending on crate internals.
+pub fn compute_signature(timestamp: i64, payload: &str, secret: &str) -> String {
+    encode_hex(compute_signature_bytes(timestamp, payload, secret))
+}
+
+/// Same as [`compute_signature`], but returns the raw HMAC-SHA256 bytes
+/// instead of hex-encoding them, so callers can compare fixed-width byte
+/// slices directly instead of their (encoding-dependent) hex representation.
+fn compute_signature_bytes(timestamp: i64, payload: &str, secret: &str) -> Vec<u8> {
+    let signed_payload = format!("{}.{}", timestamp, payload);
 
-    let result = mac.finalize();
-    let result = result.into_bytes().as_slice().to_vec();
+    let mut mac = HmacSha256::new_varkey(secret.as_bytes()).expect("HMAC can take key of any size");
+    mac.update(signed_payload.as_bytes());
 
-    encode_hex(result)
+    mac.finalize().into_bytes().as_slice().to_vec()
 }
 
-pub(crate) fn parse_stripe_signature_header(
-    header: &str,
-) -> Result<HashMap<String, String>, Box<dyn Error>> {
+/// Parses a `Stripe-Signature` header into a map of key to all values received
+/// for that key, preserving order. Stripe sends one `t` but, during secret
+/// rotation, may send several `v1` entries - one per active signing secret -
+/// so every value is kept rather than only the last one.
+pub(crate) fn parse_stripe_signature_header(header: &str) -> Result<HashMap<String, Vec<String>>, Error> {
     let signature = header.trim().to_string();
     let signature: Vec<&str> = signature.split(",").collect();
     let signature: Vec<Vec<&str>> = signature
@@ -37,17 +63,20 @@ pub(crate) fn parse_stripe_signature_header(
         .map(|pair| pair.split("=").map(|s| s.trim()).collect())
         .collect();
 
-    let mut values: HashMap<String, String> = HashMap::new();
+    let mut values: HashMap<String, Vec<String>> = HashMap::new();
 
     for pair in &signature {
         if pair.len() != 2 {
-            return Err(Box::new(InvalidKeyValuePair));
+            return Err(Error::MalformedHeader);
         }
 
         let key = pair.first();
         let value = pair.last();
 
-        values.insert(key.unwrap().to_string(), value.unwrap().to_string());
+        values
+            .entry(key.unwrap().to_string())
+            .or_insert_with(Vec::new)
+            .push(value.unwrap().to_string());
     }
 
     Ok(values)
@@ -56,43 +85,170 @@ pub(crate) fn parse_stripe_signature_header(
 /// Implements Webhook payload verification, in accordance with official Stripe docs.
 /// See [docs](https://stripe.com/docs/webhooks/signatures) for details.
 ///
+/// Rejects payloads whose `t` timestamp is older than [`DEFAULT_TOLERANCE`], to
+/// guard against replay of a previously captured payload and signature. Use
+/// [`verify_with_tolerance`] to customize the window.
+///
 /// # Errors
 ///
-/// This function will return Err whenever the payload does not contain
-/// the required entries (```v1``` and ```t```).
-pub fn verify(secret: &str, header: &str, payload: &str) -> Result<bool, Box<dyn Error>> {
+/// Returns [`Error::MissingTimestamp`] or [`Error::MissingSignature`] when the
+/// header is missing `t`/`v1`, [`Error::MalformedHeader`] when it can't be
+/// parsed, [`Error::TimestampOutOfTolerance`] when `t` falls outside the
+/// tolerance window, [`Error::InvalidSignatureEncoding`] when a `v1` entry is
+/// not valid hex, and [`Error::SignatureMismatch`] when the signature does
+/// not match.
+pub fn verify(secret: &str, header: &str, payload: &str) -> Result<(), Error> {
+    verify_with_tolerance(secret, header, payload, DEFAULT_TOLERANCE)
+}
+
+/// Like [`verify`], but with a caller-supplied replay-protection `tolerance`
+/// instead of [`DEFAULT_TOLERANCE`].
+///
+/// # Errors
+///
+/// Returns Err for the same reasons as [`verify`].
+pub fn verify_with_tolerance(
+    secret: &str,
+    header: &str,
+    payload: &str,
+    tolerance: Duration,
+) -> Result<(), Error> {
+    verify_multi_with_tolerance(&[secret], header, payload, tolerance)
+}
+
+/// Like [`verify`], but accepts several `secrets` and succeeds if ANY of them
+/// produces a signature matching ANY of the received `v1` entries.
+///
+/// This supports zero-downtime secret rotation: during rotation Stripe's
+/// webhook header carries one `v1` per active signing secret, and a consumer
+/// needs to accept whichever of its old or new secret matches.
+///
+/// # Errors
+///
+/// Returns Err for the same reasons as [`verify`].
+pub fn verify_multi(secrets: &[&str], header: &str, payload: &str) -> Result<(), Error> {
+    verify_multi_with_tolerance(secrets, header, payload, DEFAULT_TOLERANCE)
+}
+
+/// Like [`verify_multi`], but with a caller-supplied replay-protection
+/// `tolerance` instead of [`DEFAULT_TOLERANCE`].
+pub fn verify_multi_with_tolerance(
+    secrets: &[&str],
+    header: &str,
+    payload: &str,
+    tolerance: Duration,
+) -> Result<(), Error> {
     let parsed_header = parse_stripe_signature_header(header)?;
 
-    let received_timestamp = parsed_header.get("t").ok_or(InvalidKeyValuePair)?;
-    let received_signature = parsed_header.get("v1").ok_or(InvalidKeyValuePair)?;
+    let received_timestamp = parsed_header
+        .get("t")
+        .and_then(|values| values.first())
+        .ok_or(Error::MissingTimestamp)?;
+    let received_signatures = parsed_header.get("v1").ok_or(Error::MissingSignature)?;
+
+    let timestamp: i64 = received_timestamp
+        .parse()
+        .map_err(|_| Error::MalformedHeader)?;
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("time went backwards")
+        .as_secs() as i64;
+
+    let age = (now - timestamp).unsigned_abs();
+    if age > tolerance.as_secs() {
+        return Err(Error::TimestampOutOfTolerance {
+            age: Duration::from_secs(age),
+        });
+    }
+
+    let received_signatures = received_signatures
+        .iter()
+        .map(|signature| hex::decode(signature).map_err(|_| Error::InvalidSignatureEncoding))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let matches = secrets.iter().any(|secret| {
+        let expected_signature = compute_signature_bytes(timestamp, payload, secret);
+
+        received_signatures
+            .iter()
+            .any(|received_signature| constant_time_eq(&expected_signature, received_signature))
+    });
+
+    if matches {
+        Ok(())
+    } else {
+        Err(Error::SignatureMismatch)
+    }
+}
+
+/// Streaming counterpart to [`verify`] for payloads read incrementally (e.g.
+/// from a chunked HTTP body), so the whole payload never has to be buffered
+/// into a single `&str` before hashing.
+///
+/// Feed the payload bytes via [`std::io::Write`] (e.g. `io::copy(&mut body,
+/// &mut verifier)`), then call [`Verifier::verify`] with the received `v1`
+/// signature to finalize the MAC and compare.
+pub struct Verifier {
+    mac: HmacSha256,
+}
 
-    let payload = format!("{}.{}", received_timestamp, payload);
-    let expected_signature = &compute_signature(&payload, &secret);
+impl Verifier {
+    /// Starts a new verifier for a payload signed at `timestamp` with `secret`.
+    pub fn new(secret: &str, timestamp: i64) -> Self {
+        let mut mac =
+            HmacSha256::new_varkey(secret.as_bytes()).expect("HMAC can take key of any size");
+        mac.update(format!("{}.", timestamp).as_bytes());
 
-    Ok(constant_time_eq(
-        expected_signature.as_bytes(),
-        received_signature.as_bytes(),
-    ))
+        Verifier { mac }
+    }
+
+    /// Finalizes the MAC over everything written so far and constant-time
+    /// compares it against `received_sig`.
+    pub fn verify(self, received_sig: &str) -> bool {
+        let result = self.mac.finalize().into_bytes().as_slice().to_vec();
+        let expected_signature = encode_hex(result);
+
+        constant_time_eq(expected_signature.as_bytes(), received_sig.as_bytes())
+    }
+}
+
+impl io::Write for Verifier {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.mac.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::time::{SystemTime, UNIX_EPOCH};
+    use std::io;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
     use crate::compute_signature;
     use crate::parse_stripe_signature_header;
     use crate::verify;
+    use crate::verify_multi;
+    use crate::verify_with_tolerance;
+    use crate::Error;
+    use crate::Verifier;
 
     pub(crate) fn generate_test_header(payload: String) -> String {
-        let start = SystemTime::now();
-        let timestamp = start
+        generate_test_header_at(payload, now())
+    }
+
+    fn now() -> i64 {
+        SystemTime::now()
             .duration_since(UNIX_EPOCH)
             .expect("time went backwards")
-            .as_secs()
-            .to_string();
+            .as_secs() as i64
+    }
 
-        let payload = format!("{}.{}", timestamp, payload);
-        let signature = compute_signature(&payload, "really secure secret");
+    fn generate_test_header_at(payload: String, timestamp: i64) -> String {
+        let signature = compute_signature(timestamp, &payload, "really secure secret");
 
         let header = format!("t={},v1={},v0=", timestamp, signature);
 
@@ -114,6 +270,85 @@ mod tests {
             &stripe_signature_header,
             "sample payload",
         )
-        .unwrap());
+        .is_ok());
+    }
+
+    #[test]
+    fn it_rejects_replayed_payloads_outside_tolerance() {
+        let stale_timestamp = now() - 301;
+        let stripe_signature_header =
+            generate_test_header_at("sample payload".to_string(), stale_timestamp);
+
+        let result = verify(
+            "really secure secret",
+            &stripe_signature_header,
+            "sample payload",
+        );
+
+        assert!(matches!(result, Err(Error::TimestampOutOfTolerance { .. })));
+    }
+
+    #[test]
+    fn it_computes_a_signature_usable_by_verify() {
+        let timestamp = now();
+        let signature = compute_signature(timestamp, "sample payload", "really secure secret");
+        let header = format!("t={},v1={}", timestamp, signature);
+
+        assert!(verify("really secure secret", &header, "sample payload").is_ok());
+    }
+
+    #[test]
+    fn it_verifies_against_any_of_several_rotated_secrets() {
+        let timestamp = now();
+        let old_signature = compute_signature(timestamp, "sample payload", "old secret");
+        let new_signature = compute_signature(timestamp, "sample payload", "new secret");
+        let header = format!(
+            "t={},v1={},v1={}",
+            timestamp, old_signature, new_signature
+        );
+
+        assert!(verify_multi(&["old secret", "new secret"], &header, "sample payload").is_ok());
+        assert!(matches!(
+            verify_multi(&["unrelated secret"], &header, "sample payload"),
+            Err(Error::SignatureMismatch)
+        ));
+    }
+
+    #[test]
+    fn it_rejects_a_non_hex_signature() {
+        let timestamp = now();
+        let header = format!("t={},v1=not hex at all", timestamp);
+
+        assert!(matches!(
+            verify("really secure secret", &header, "sample payload"),
+            Err(Error::InvalidSignatureEncoding)
+        ));
+    }
+
+    #[test]
+    fn it_verifies_a_streamed_payload() {
+        let timestamp = now();
+        let signature = compute_signature(timestamp, "sample payload", "really secure secret");
+
+        let mut body = io::Cursor::new(b"sample payload".to_vec());
+        let mut verifier = Verifier::new("really secure secret", timestamp);
+        io::copy(&mut body, &mut verifier).unwrap();
+
+        assert!(verifier.verify(&signature));
+    }
+
+    #[test]
+    fn it_accepts_a_custom_tolerance() {
+        let stale_timestamp = now() - 301;
+        let stripe_signature_header =
+            generate_test_header_at("sample payload".to_string(), stale_timestamp);
+
+        assert!(verify_with_tolerance(
+            "really secure secret",
+            &stripe_signature_header,
+            "sample payload",
+            Duration::from_secs(600),
+        )
+        .is_ok());
     }
 }